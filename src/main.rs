@@ -1,6 +1,10 @@
 use std::collections::HashMap;
 use std::io::Result;
 use std::net::Ipv4Addr;
+use std::os::unix::io::AsRawFd;
+use std::time::Instant;
+
+use nix::poll::{poll, PollFd, PollFlags};
 
 mod tcp;
 
@@ -10,12 +14,62 @@ struct Quad {
     dst: (Ipv4Addr, u16),
 }
 
+/// Parse a `TRUST_CONNECT` spec of the form `src_ip:src_port:dst_ip:dst_port`
+/// into the local and remote endpoints for an active open.
+fn parse_connect(spec: &str) -> Option<((Ipv4Addr, u16), (Ipv4Addr, u16))> {
+    let parts: Vec<&str> = spec.split(':').collect();
+    if parts.len() != 4 {
+        return None;
+    }
+    let src = (parts[0].parse().ok()?, parts[1].parse().ok()?);
+    let dst = (parts[2].parse().ok()?, parts[3].parse().ok()?);
+    Some((src, dst))
+}
+
 fn main() -> Result<()> {
     let mut connections: HashMap<Quad, tcp::Connection> = Default::default();
     let mut nic = tun_tap::Iface::without_packet_info("tun0", tun_tap::Mode::Tun)?;
     // Configure the device ‒ set IP address on it, bring it up.
     let mut buf = vec![0; 1504]; // MTU
+
+    // Optional active open driven by TRUST_CONNECT: send the initial SYN now
+    // and key the TCB by the remote as source, matching how inbound replies
+    // from the peer are keyed below.
+    if let Some((src, dst)) = std::env::var("TRUST_CONNECT").ok().as_deref().and_then(parse_connect) {
+        let c = tcp::Connection::connect(&mut nic, src, dst)?;
+        connections.insert(Quad { src: dst, dst: src }, c);
+    }
     loop {
+        // Bound the wait on the NIC by the nearest retransmission deadline
+        // across all connections, so timers actually fire while we're idle.
+        let now = Instant::now();
+        let next = connections
+            .values()
+            .filter_map(|c| c.next_timeout())
+            .min();
+        let timeout_ms = match next {
+            Some(t) if t > now => t.duration_since(now).as_millis() as i32,
+            Some(_) => 0,
+            None => -1, // nothing in flight: block until a packet arrives
+        };
+
+        let mut fds = [PollFd::new(nic.as_raw_fd(), PollFlags::POLLIN)];
+        let ready = poll(&mut fds, timeout_ms)
+            .map_err(|e| std::io::Error::from_raw_os_error(e as i32))?;
+        if ready == 0 {
+            // poll timed out: service timers and reap any connection whose
+            // TIME-WAIT (or other) timer has closed it out.
+            connections.retain(|_quad, c| match c.on_tick(&mut nic) {
+                Ok(tcp::Disposition::Remove) => false,
+                Ok(tcp::Disposition::Keep) => true,
+                Err(e) => {
+                    eprintln!("on_tick error: {:?}", e);
+                    true
+                }
+            });
+            continue;
+        }
+
         let nbytes = nic.recv(&mut buf[..])?;
         // let eth_flags = u16::from_be_bytes([buf[0], buf[1]]);
         // let eth_proto = u16::from_be_bytes([buf[2], buf[3]]);
@@ -44,12 +98,16 @@ fn main() -> Result<()> {
                         };
                         match connections.entry(quad) {
                             Entry::Occupied(mut c) => {
-                                c.get_mut().on_packet(
+                                let disposition = c.get_mut().on_packet(
                                     &mut nic,
                                     &iph,
                                     &tcph,
                                     &buf[datai..nbytes],
                                 )?;
+                                if disposition == tcp::Disposition::Remove {
+                                    // TIME-WAIT/CLOSED: the TCB is done, reap it
+                                    c.remove();
+                                }
                             }
                             Entry::Vacant(e) => {
                                 if let Some(c) = tcp::Connection::accept(
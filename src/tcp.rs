@@ -1,23 +1,68 @@
+use std::collections::VecDeque;
 use std::io;
+use std::time::{Duration, Instant};
+
+mod congestion;
+use congestion::CongestionControl;
+
+use etherparse::TcpOptionElement;
+
+/// Maximum Segment Lifetime. Fuchsia's netstack uses 2 minutes; TIME-WAIT
+/// lasts twice this.
+const MSL: Duration = Duration::from_secs(2 * 60);
+
+/// MSS we advertise and cap our own segments to; the negotiated value is the
+/// smaller of this and the peer's advertised MSS.
+const OUR_MSS: u16 = 1460;
+
+/// Transmit sink the connection writes finished segments to. The running
+/// stack uses the tun device; tests substitute a sink that discards segments so the
+/// state machine can be driven without a live NIC.
+pub(crate) trait Nic {
+    fn send(&mut self, buf: &[u8]) -> io::Result<usize>;
+}
+
+impl Nic for tun_tap::Iface {
+    fn send(&mut self, buf: &[u8]) -> io::Result<usize> {
+        tun_tap::Iface::send(self, buf)
+    }
+}
+
+/// What the event loop should do with a connection after servicing it.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Disposition {
+    /// keep the connection's TCB in the quad table
+    Keep,
+    /// the connection is fully closed: reap its TCB
+    Remove,
+}
 
 // TCP 状态枚举
 enum State {
-    // Listen,
+    Closed,
+    Listen,
+    SynSent,
     SyncRcvd,
     Estab,
     FinWait1,
     FinWait2,
+    Closing,
     CloseWait,
+    LastAck,
     TimeWait,
-    // LastAck,
-    // Closed,
 }
 
 impl State {
     fn is_synchonized(&self) -> bool {
         match self {
-            State::Estab | State::FinWait1 | State::FinWait2 | State::CloseWait | State::TimeWait => true,
-            State::SyncRcvd => false,
+            State::Estab
+            | State::FinWait1
+            | State::FinWait2
+            | State::Closing
+            | State::CloseWait
+            | State::LastAck
+            | State::TimeWait => true,
+            State::Closed | State::Listen | State::SynSent | State::SyncRcvd => false,
         }
     }
 }
@@ -29,6 +74,171 @@ pub(crate) struct Connection {
     recv: RecvSequenceSpace,
     tcp: etherparse::TcpHeader,
     ip: etherparse::Ipv4Header,
+    /// byte ring buffer holding in-window received data, indexed from recv.nxt
+    incoming: std::collections::VecDeque<u8>,
+    /// holes over the receive window so recv.nxt only advances over contiguous data
+    assembler: Assembler,
+    /// segments from send.una..send.nxt still awaiting acknowledgement, oldest first
+    unacked: VecDeque<Segment>,
+    /// RTT estimator and retransmission timeout driving the timer subsystem
+    timers: Timers,
+    /// slow start / congestion avoidance state bounding the send window
+    congestion: CongestionControl,
+    /// sequence number of our FIN once we have sent one, for detecting its ACK
+    closed_at: Option<u32>,
+    /// when we entered TIME-WAIT, for arming the 2·MSL teardown timer
+    time_wait_at: Option<Instant>,
+    /// negotiated maximum segment size we cap outgoing segments to
+    mss: u16,
+    /// scale applied to the peer's advertised window (from its WS option)
+    send_wscale: u8,
+    /// scale we advertise for our own receive window. Kept at 0 while the
+    /// receive buffer is a fixed 1024 bytes: a non-zero shift would invite the
+    /// peer to send far past the buffer. Raise it only alongside a buffer sized
+    /// to the scaled window.
+    recv_wscale: u8,
+    /// whether window scaling was negotiated (both SYNs carried a WS option).
+    /// We still advertise a WS option of shift 0 so the peer keeps scaling its
+    /// own window, which `send_wscale` then applies.
+    scaling: bool,
+}
+
+/// A segment parked in the retransmission queue until it is acknowledged.
+struct Segment {
+    /// sequence number of the first octet (or SYN/FIN) it carries
+    seq: u32,
+    /// payload bytes (empty for a bare SYN or FIN)
+    data: Vec<u8>,
+    syn: bool,
+    fin: bool,
+    /// when the segment was last (re)transmitted
+    sent: Instant,
+    /// set once the segment is retransmitted, so Karn's algorithm skips its RTT
+    retransmitted: bool,
+}
+
+impl Segment {
+    /// sequence space the segment consumes (payload length plus SYN/FIN)
+    fn len(&self) -> u32 {
+        self.data.len() as u32 + self.syn as u32 + self.fin as u32
+    }
+}
+
+/// Smoothed RTT estimator driving the retransmission timeout (RFC 6298 / RFC
+/// 793 adaptive retransmission). `srtt` and `rttvar` are kept in seconds.
+struct Timers {
+    /// smoothed round-trip time
+    srtt: f64,
+    /// round-trip time variation
+    rttvar: f64,
+    /// current retransmission timeout
+    rto: Duration,
+    /// whether a first sample has been taken yet
+    sampled: bool,
+}
+
+impl Timers {
+    /// gains from RFC 6298
+    const ALPHA: f64 = 1.0 / 8.0;
+    const BETA: f64 = 1.0 / 4.0;
+    /// the RFC-mandated lower bound on the RTO
+    const MIN_RTO: Duration = Duration::from_secs(1);
+
+    fn new() -> Self {
+        Timers {
+            srtt: 0.0,
+            rttvar: 0.0,
+            rto: Self::MIN_RTO,
+            sampled: false,
+        }
+    }
+
+    /// Fold a fresh RTT sample into the estimator and recompute the RTO.
+    fn sample(&mut self, rtt: Duration) {
+        let r = rtt.as_secs_f64();
+        if !self.sampled {
+            self.srtt = r;
+            self.rttvar = r / 2.0;
+            self.sampled = true;
+        } else {
+            self.rttvar = (1.0 - Self::BETA) * self.rttvar + Self::BETA * (self.srtt - r).abs();
+            self.srtt = (1.0 - Self::ALPHA) * self.srtt + Self::ALPHA * r;
+        }
+        let rto = Duration::from_secs_f64(self.srtt + 4.0 * self.rttvar);
+        self.rto = std::cmp::max(rto, Self::MIN_RTO);
+    }
+
+    /// Double the RTO after a timeout (exponential backoff).
+    fn backoff(&mut self) {
+        self.rto = std::cmp::max(self.rto * 2, Self::MIN_RTO);
+    }
+}
+
+/// Tracks which parts of the receive window have been filled by (possibly
+/// out-of-order) segments, so that `recv.nxt` only advances across a
+/// contiguous prefix. Modelled on smoltcp's `storage::Assembler`, but the gaps
+/// ("holes") are stored directly as sorted, non-overlapping `(offset, len)`
+/// intervals measured from the left edge of the window (i.e. from `recv.nxt`).
+struct Assembler {
+    /// total size of the window this assembler covers
+    window: usize,
+    /// sorted, non-overlapping holes still to be filled, as `(offset, len)`
+    holes: Vec<(usize, usize)>,
+}
+
+impl Assembler {
+    /// A fresh assembler whose whole window is one big hole.
+    fn new(window: usize) -> Self {
+        Assembler {
+            window,
+            holes: vec![(0, window)],
+        }
+    }
+
+    /// Mark `[offset, offset + len)` as received, splitting or shrinking the
+    /// holes it overlaps. Bytes outside the window are ignored by the caller.
+    fn add(&mut self, offset: usize, len: usize) {
+        if len == 0 {
+            return;
+        }
+        let end = offset + len;
+        let mut next = Vec::with_capacity(self.holes.len() + 1);
+        for &(h_start, h_len) in &self.holes {
+            let h_end = h_start + h_len;
+            // No overlap: the hole survives untouched.
+            if end <= h_start || offset >= h_end {
+                next.push((h_start, h_len));
+                continue;
+            }
+            // The part of the hole left of the filled range survives, ...
+            if offset > h_start {
+                next.push((h_start, offset - h_start));
+            }
+            // ... as does the part right of it.
+            if end < h_end {
+                next.push((end, h_end - end));
+            }
+        }
+        self.holes = next;
+    }
+
+    /// Number of contiguous bytes available at the left edge of the window.
+    fn contiguous(&self) -> usize {
+        self.holes.first().map_or(self.window, |&(start, _)| start)
+    }
+
+    /// Slide the window left by `n` bytes once its leading `n` bytes have been
+    /// handed to the application, exposing `n` fresh bytes on the right.
+    fn consume(&mut self, n: usize) {
+        for hole in &mut self.holes {
+            hole.0 -= n;
+        }
+        let right = self.window - n;
+        match self.holes.last_mut() {
+            Some(last) if last.0 + last.1 == right => last.1 += n,
+            _ => self.holes.push((right, n)),
+        }
+    }
 }
 
 /// State of the Send Sequence Space (RFC 793 S3.2 F4)
@@ -85,9 +295,28 @@ struct RecvSequenceSpace {
 }
 
 impl Connection {
+    /// Read the MSS and window-scale options off a SYN, returning the
+    /// negotiated send MSS (clamped to our own) and the peer's window scale
+    /// (`None` if it offered no WS option, meaning scaling stays disabled).
+    fn parse_syn_options(tcph: &etherparse::TcpHeaderSlice) -> (u16, Option<u8>) {
+        // RFC 879 default MSS when the peer advertises none.
+        let mut mss = 536;
+        let mut wscale = None;
+        for opt in tcph.options_iterator().flatten() {
+            match opt {
+                TcpOptionElement::MaximumSegmentSize(m) => mss = std::cmp::min(OUR_MSS, m),
+                // RFC 7323 caps the window scale at 14; clamp so send_window's
+                // shift can never overflow on a hostile or malformed option.
+                TcpOptionElement::WindowScale(s) => wscale = Some(std::cmp::min(s, 14)),
+                _ => {}
+            }
+        }
+        (mss, wscale)
+    }
+
     // 接收到nic上来的一个网络包之后，构建连接的过程
     pub(crate) fn accept<'a>(
-        nic: &mut tun_tap::Iface,
+        nic: &mut impl Nic,
         iph: &etherparse::Ipv4HeaderSlice,
         tcph: &etherparse::TcpHeaderSlice,
         data: &[u8],
@@ -96,20 +325,29 @@ impl Connection {
         // 因为是模拟的server端的状态，所以默认就是在listening的状态
         // 到来的包不是rcv包的话，就直接拒掉
         if !tcph.syn() {
-            // only expecting SYN packets
+            // no TCB for this quad and not a SYN: reject the stray segment
+            // with a RST (RFC 793 S3.4) rather than silently dropping it.
+            Self::send_reset(nic, iph, tcph, data)?;
             return Ok(None);
         }
 
         let iss = 0;
         let wnd = 1024;
+        // negotiate options carried on the incoming SYN
+        let (mss, peer_wscale) = Self::parse_syn_options(tcph);
         // 构建一个TCP连接
         let mut c = Connection {
             state: State::SyncRcvd,
             send: SendSequenceSpace {
                 iss,
                 una: iss,
-                nxt: iss + 1,
-                wnd: wnd,
+                // write() advances nxt over the SYN, so seed it at the ISS
+                // (matching connect()); otherwise the SYN-ACK ships at iss+1
+                // and leaves a phantom sequence number wedged below it.
+                nxt: iss,
+                // the peer's advertised receive window bounds what we may send,
+                // not our own receive window
+                wnd: tcph.window_size(),
                 up: false,
                 wl1: 0,
                 wl2: 0,
@@ -117,7 +355,9 @@ impl Connection {
             recv: RecvSequenceSpace {
                 irs: tcph.sequence_number(),
                 nxt: tcph.sequence_number() + 1,
-                wnd: tcph.window_size(),
+                // our own receive window — the value we advertise and size the
+                // reassembly buffer to — not the peer's (that bounds our send)
+                wnd,
                 up: false,
             },
             // need to start establishing the connection
@@ -129,6 +369,20 @@ impl Connection {
                 iph.destination().into(),
                 iph.source().into(),
             ),
+            incoming: std::collections::VecDeque::from(vec![0u8; wnd as usize]),
+            assembler: Assembler::new(wnd as usize),
+            unacked: VecDeque::new(),
+            timers: Timers::new(),
+            congestion: CongestionControl::new(mss as u32),
+            closed_at: None,
+            time_wait_at: None,
+            mss,
+            send_wscale: peer_wscale.unwrap_or(0),
+            // our 1024-byte receive buffer cannot back a scaled window, so we
+            // advertise shift 0; scaling is still negotiated (and applied to
+            // the peer's window) whenever the peer offered a WS option
+            recv_wscale: 0,
+            scaling: peer_wscale.is_some(),
         };
         c.tcp.syn = true;
         c.tcp.ack = true;
@@ -136,14 +390,126 @@ impl Connection {
         Ok(Some(c))
     }
 
+    /// Actively open a connection: send the initial SYN and enter SYN-SENT.
+    /// The receive sequence space is filled in once the peer's SYN arrives.
+    pub(crate) fn connect(
+        nic: &mut impl Nic,
+        src: (std::net::Ipv4Addr, u16),
+        dst: (std::net::Ipv4Addr, u16),
+    ) -> io::Result<Self> {
+        let iss = 0;
+        let wnd = 1024;
+        let mut c = Connection {
+            state: State::SynSent,
+            send: SendSequenceSpace {
+                iss,
+                una: iss,
+                nxt: iss,
+                wnd,
+                up: false,
+                wl1: 0,
+                wl2: 0,
+            },
+            recv: RecvSequenceSpace {
+                // unknown until we receive the peer's SYN
+                irs: 0,
+                nxt: 0,
+                wnd: 0,
+                up: false,
+            },
+            tcp: etherparse::TcpHeader::new(src.1, dst.1, iss, wnd),
+            ip: etherparse::Ipv4Header::new(0, 64, 6, src.0.octets(), dst.0.octets()),
+            incoming: std::collections::VecDeque::from(vec![0u8; wnd as usize]),
+            assembler: Assembler::new(wnd as usize),
+            unacked: VecDeque::new(),
+            timers: Timers::new(),
+            congestion: CongestionControl::new(OUR_MSS as u32),
+            closed_at: None,
+            time_wait_at: None,
+            // offer our full MSS and window scaling (at shift 0, since our
+            // receive buffer is 1024 bytes); narrowed by the SYN-ACK
+            mss: OUR_MSS,
+            send_wscale: 0,
+            recv_wscale: 0,
+            scaling: true,
+        };
+        c.tcp.syn = true;
+        c.write(nic, &[])?;
+        Ok(c)
+    }
+
+    /// Handle a segment received while in SYN-SENT (RFC 793 S3.9). We expect a
+    /// SYN — with an ACK for the normal three-way handshake, or without one for
+    /// a simultaneous open.
+    fn on_synsent(
+        &mut self,
+        nic: &mut impl Nic,
+        tcph: &etherparse::TcpHeaderSlice,
+    ) -> io::Result<()> {
+        if !tcph.syn() {
+            // nothing actionable without a SYN; RST handling lives elsewhere
+            return Ok(());
+        }
+        // record the peer's initial receive sequence space; the peer's window
+        // bounds what we may send, while our own receive window stays the value
+        // we advertised (and sized the reassembly buffer to)
+        self.recv.irs = tcph.sequence_number();
+        self.recv.nxt = tcph.sequence_number().wrapping_add(1);
+        self.recv.wnd = self.assembler.window as u16;
+        self.send.wnd = tcph.window_size();
+
+        // negotiate options from the peer's SYN / SYN-ACK
+        let (mss, peer_wscale) = Self::parse_syn_options(tcph);
+        self.mss = mss;
+        match peer_wscale {
+            Some(s) => self.send_wscale = s,
+            // peer won't scale, so neither do we
+            None => {
+                self.send_wscale = 0;
+                self.scaling = false;
+            }
+        }
+
+        if tcph.ack() {
+            let ackn = tcph.acknowledgment_number();
+            if !is_between_wrapped(self.send.una, ackn, self.send.nxt.wrapping_add(1)) {
+                // our SYN was not acknowledged; drop and let it retransmit
+                return Ok(());
+            }
+            // retire using the old una as the lower bound, then advance it
+            self.on_ack(ackn);
+            self.send.una = ackn;
+            self.state = State::Estab;
+            // acknowledge the peer's SYN
+            self.tcp.ack = true;
+            self.write(nic, &[])?;
+        } else {
+            // simultaneous open: turn our already-queued SYN into a SYN-ACK and
+            // resend it. A fresh write() would emit at send.nxt (= ISS+1) and
+            // queue a second, never-acked SYN; RFC 793 requires the SYN-ACK to
+            // carry SEG.SEQ = ISS, which retransmit_oldest does.
+            self.tcp.ack = true;
+            self.state = State::SyncRcvd;
+            self.retransmit_oldest(nic)?;
+        }
+        Ok(())
+    }
+
     // 接收到一个包之后，处理网络包的过程
     pub(crate) fn on_packet(
         &mut self,
-        nic: &mut tun_tap::Iface,
+        nic: &mut impl Nic,
         iph: &etherparse::Ipv4HeaderSlice,
         tcph: &etherparse::TcpHeaderSlice,
         data: &[u8],
-    ) -> io::Result<()> {
+    ) -> io::Result<Disposition> {
+        // SYN-SENT has its own acceptance rules (the receive space is not set
+        // up yet), so handle it before the normal sequence-number checks.
+        if let State::SynSent = self.state {
+            self.on_synsent(nic, tcph)?;
+            return Ok(self.disposition());
+        }
+
         // first, check that sequence numbers are valid (RFC 793 S3.3)
         let seqn = tcph.sequence_number();
         let mut slen = data.len() as u32;
@@ -151,7 +517,8 @@ impl Connection {
         if tcph.fin() {
             slen += 1;
         };
-        if tcph.ack() {
+        if tcph.syn() {
+            // SYN (like FIN) consumes one sequence number; an ACK does not
             slen += 1;
         };
 
@@ -195,12 +562,59 @@ impl Connection {
 
         if !okay {
             self.write(nic, &[])?;
-            return Ok(());
+            return Ok(self.disposition());
+        }
+
+        // Fold the payload into the reassembly buffer. recv.nxt only advances
+        // over the contiguous prefix starting at the current left edge, so
+        // reordered segments are buffered until their predecessors arrive.
+        if !data.is_empty() {
+            // Offset of the segment relative to the left edge of the window.
+            // A segment that starts before recv.nxt has a negative (wrapped)
+            // offset; trim the part we've already accepted.
+            let rel = seqn.wrapping_sub(self.recv.nxt) as i32;
+            let (offset, data) = if rel < 0 {
+                let trim = (-rel) as usize;
+                if trim >= data.len() {
+                    // entirely to the left of recv.nxt: ack and drop
+                    (None, &data[..0])
+                } else {
+                    (Some(0usize), &data[trim..])
+                }
+            } else {
+                (Some(rel as usize), data)
+            };
+
+            if let Some(offset) = offset {
+                // the ring and the assembler cover *our* advertised receive
+                // window (what we put in the SYN-ACK); offsets at or past it
+                // would run off the end of the buffer
+                let our_wnd = self.assembler.window;
+                if offset < our_wnd {
+                    // clip to what fits in our receive buffer
+                    let n = std::cmp::min(data.len(), our_wnd - offset);
+                    for (i, &b) in data[..n].iter().enumerate() {
+                        self.incoming[offset + i] = b;
+                    }
+                    self.assembler.add(offset, n);
+                    let advance = self.assembler.contiguous();
+                    if advance > 0 {
+                        self.recv.nxt = self.recv.nxt.wrapping_add(advance as u32);
+                        self.assembler.consume(advance);
+                        // drop the contiguous prefix from the ring (nothing
+                        // reads it out) and refill it so it stays window-sized
+                        self.incoming.drain(..advance);
+                        self.incoming.resize(our_wnd, 0);
+                    }
+                }
+            }
+
+            // acknowledge what we now have contiguously
+            self.write(nic, &[])?;
         }
-        self.recv.nxt = seqn.wrapping_add(slen);
 
         if !tcph.ack() {
-            return Ok(());
+            return Ok(self.disposition());
         }
 
         let ackn = tcph.acknowledgment_number();
@@ -212,54 +626,192 @@ impl Connection {
             ) {
                 // must have ACKed our SYN, since we detected at least one acked byte,
                 // and we have only sent one byte (the SYN).
+                // retire using the old una as the lower bound, then advance it
+                self.on_ack(ackn);
+                self.send.una = ackn;
                 self.state = State::Estab;
             } else {
-                // TODO: <SEQ=SEG.ACK><CTL=RST>
+                // unacceptable ACK in an unsynchronized state: <SEQ=SEG.ACK><CTL=RST>
+                // then abort — RFC 793 deletes the TCB, so ask to be reaped.
+                self.send_rst(nic, tcph, data)?;
+                self.state = State::Closed;
+                return Ok(self.disposition());
             }
         }
 
-        if let State::Estab | State::FinWait1 | State::FinWait2 = self.state {
-            if !is_between_wrapped(self.send.una, ackn, self.send.nxt.wrapping_add(1)) {
-                return Ok(());
-            }
-            self.send.una = ackn;
-            // TODO
-            assert!(data.is_empty());
-
-            if let State::Estab = self.state {
-                // now let's terminate the connection!
-                // TODO: needs to be stored in the retransmission queue!
-                self.tcp.fin = true;
-                self.write(nic, &[])?;
-                self.state = State::FinWait1;
-            }
-        }
-
-        if let State::FinWait1 = self.state {
-            if self.send.una == self.send.iss + 2 {
-                // our FIN has been ACKed!
-                self.state = State::FinWait2;
+        // ACK processing for every synchronized state but TIME-WAIT (RFC 793 S3.9).
+        if let State::Estab
+        | State::FinWait1
+        | State::FinWait2
+        | State::Closing
+        | State::CloseWait
+        | State::LastAck = self.state
+        {
+            if is_between_wrapped(self.send.una, ackn, self.send.nxt.wrapping_add(1)) {
+                // retire acknowledged segments and update the RTT estimate using
+                // the old una as the lower bound, *then* advance una
+                self.on_ack(ackn);
+                self.send.una = ackn;
+                // track the peer's advertised window (scaled) for flow control
+                self.send.wnd = tcph.window_size();
+                // open the congestion window
+                self.congestion.on_ack();
+            } else if ackn == self.send.una && data.is_empty() && !self.unacked.is_empty()
+                && self.congestion.on_dup_ack()
+            {
+                // Not a new ACK. A bare, empty ACK that re-acks send.una while
+                // data is still in flight is a duplicate ACK; three in a row
+                // trigger fast retransmit / fast recovery. Fall through either
+                // way so a FIN riding on a non-advancing ACK is still processed
+                // below rather than dropped.
+                self.retransmit_oldest(nic)?;
             }
         }
 
+        // Process an incoming FIN *before* the eager close, so that a FIN
+        // received in ESTABLISHED routes Estab→CloseWait (passive close)
+        // rather than being pre-empted by the active close below.
         if tcph.fin() {
             match self.state {
+                // passive close: the peer is done sending; ack and wait until
+                // our own side closes.
+                State::Estab => {
+                    self.recv.nxt = self.recv.nxt.wrapping_add(1);
+                    self.write(nic, &[])?;
+                    self.state = State::CloseWait;
+                }
+                // simultaneous close: our FIN is still unacked when the peer's
+                // FIN arrives.
+                State::FinWait1 => {
+                    self.recv.nxt = self.recv.nxt.wrapping_add(1);
+                    self.write(nic, &[])?;
+                    self.state = State::Closing;
+                }
                 State::FinWait2 => {
-                    // we're done with the connection!
+                    self.recv.nxt = self.recv.nxt.wrapping_add(1);
+                    self.write(nic, &[])?;
+                    self.enter_time_wait();
+                }
+                // a retransmitted FIN in a closing state: just re-acknowledge
+                // and stay put.
+                State::Closing | State::CloseWait | State::LastAck => {
+                    self.write(nic, &[])?;
+                }
+                // in TIME-WAIT a retransmitted FIN must also restart the
+                // 2·MSL timer (RFC 793) so the guard covers the peer's resend.
+                State::TimeWait => {
                     self.write(nic, &[])?;
-                    self.state = State::TimeWait;
+                    self.time_wait_at = Some(Instant::now());
                 }
-                _ => unimplemented!(),
+                _ => {}
             }
         }
 
-        Ok(())
+        // Eagerly close our half of the connection once there is nothing more
+        // to send: from ESTABLISHED this is the active close, from CLOSE-WAIT
+        // it is our response to the peer's earlier FIN (→ LAST-ACK).
+        if let State::Estab = self.state {
+            self.tcp.fin = true;
+            self.write(nic, &[])?;
+            self.closed_at = Some(self.send.nxt.wrapping_sub(1));
+            self.state = State::FinWait1;
+        } else if let State::CloseWait = self.state {
+            self.tcp.fin = true;
+            self.write(nic, &[])?;
+            self.closed_at = Some(self.send.nxt.wrapping_sub(1));
+            self.state = State::LastAck;
+        }
+
+        // React to our own FIN being acknowledged.
+        if self.fin_acked() {
+            match self.state {
+                State::FinWait1 => self.state = State::FinWait2,
+                State::Closing => self.enter_time_wait(),
+                State::LastAck => self.state = State::Closed,
+                _ => {}
+            }
+        }
+
+        Ok(self.disposition())
+    }
+
+    /// Enter TIME-WAIT and arm the 2·MSL teardown timer.
+    fn enter_time_wait(&mut self) {
+        self.state = State::TimeWait;
+        self.time_wait_at = Some(Instant::now());
+    }
+
+    /// Whether our own FIN (if we have sent one) has been acknowledged.
+    fn fin_acked(&self) -> bool {
+        self.closed_at
+            .is_some_and(|fin_seq| self.send.una == fin_seq.wrapping_add(1))
     }
 
-    fn write(&mut self, nic: &mut tun_tap::Iface, payload: &[u8]) -> io::Result<usize> {
+    fn write(&mut self, nic: &mut impl Nic, payload: &[u8]) -> io::Result<usize> {
+        // bound the data by one MSS per segment and by the room left in the
+        // effective send window (peer's advertised window clamped by cwnd), so
+        // we never put more than min(send.wnd, cwnd) bytes in flight
+        let in_flight = self.send.nxt.wrapping_sub(self.send.una);
+        let window = self.send_window().saturating_sub(in_flight) as usize;
+        let limit = std::cmp::min(std::cmp::min(payload.len(), self.mss as usize), window);
+        let payload = &payload[..limit];
+        // a fresh segment always starts at the next unsent sequence number,
+        // carrying whatever control flags the caller armed on self.tcp
+        let seq = self.send.nxt;
+        let syn = self.tcp.syn;
+        let fin = self.tcp.fin;
+        let payload_bytes = self.send_segment(nic, seq, syn, fin, payload)?;
+
+        // advance the send sequence space over the data and any SYN/FIN
+        let consumed = payload_bytes as u32 + syn as u32 + fin as u32;
+        self.send.nxt = self.send.nxt.wrapping_add(consumed);
+
+        // park anything that occupies sequence space for retransmission
+        if consumed > 0 {
+            self.unacked.push_back(Segment {
+                seq,
+                data: payload[..payload_bytes].to_vec(),
+                syn,
+                fin,
+                sent: Instant::now(),
+                retransmitted: false,
+            });
+        }
+        Ok(payload_bytes)
+    }
+
+    /// Serialise and transmit a single segment with the given sequence number,
+    /// control flags and payload. Leaves the sequence space and retransmission
+    /// queue untouched, so it serves both fresh sends and retransmits.
+    fn send_segment(
+        &mut self,
+        nic: &mut impl Nic,
+        seq: u32,
+        syn: bool,
+        fin: bool,
+        payload: &[u8],
+    ) -> io::Result<usize> {
         let mut buf = [0u8; 1500];
-        self.tcp.sequence_number = self.send.nxt;
+        self.tcp.sequence_number = seq;
         self.tcp.acknowledgment_number = self.recv.nxt;
+        self.tcp.syn = syn;
+        self.tcp.fin = fin;
+
+        // a SYN/SYN-ACK carries our MSS option, plus a window-scale option
+        // only when scaling was negotiated (RFC 7323: a SYN-ACK may carry WS
+        // only if the SYN did); every other segment carries no options
+        if syn {
+            let mss = TcpOptionElement::MaximumSegmentSize(OUR_MSS);
+            let result = if self.scaling {
+                self.tcp
+                    .set_options(&[mss, TcpOptionElement::WindowScale(self.recv_wscale)])
+            } else {
+                self.tcp.set_options(&[mss])
+            };
+            result.expect("SYN options fit the header");
+        } else {
+            self.tcp.set_options(&[]).ok();
+        }
 
         let size = std::cmp::min(
             buf.len(),
@@ -281,39 +833,213 @@ impl Connection {
         self.tcp.write(&mut unwritten);
         let payload_bytes = unwritten.write(payload)?;
         let unwritten = unwritten.len();
-        self.send.nxt = self.send.nxt.wrapping_add(payload_bytes as u32);
-        if self.tcp.syn {
-            self.send.nxt = self.send.nxt.wrapping_add(1);
-            self.tcp.syn = false;
-        }
-        if self.tcp.fin {
-            self.send.nxt = self.send.nxt.wrapping_add(1);
-            self.tcp.fin = false;
-        }
         nic.send(&buf[..buf.len() - unwritten])?;
+
+        // the control flags are one-shot; clear them so the next fresh send
+        // doesn't inherit a stale SYN/FIN
+        self.tcp.syn = false;
+        self.tcp.fin = false;
         Ok(payload_bytes)
     }
 
-    fn send_rst(&mut self, nic: &mut tun_tap::Iface) -> io::Result<()> {
+    /// Retire every segment the peer has now acknowledged, taking an RTT
+    /// sample from the oldest one that was never retransmitted (Karn's
+    /// algorithm).
+    fn on_ack(&mut self, ackn: u32) {
+        let now = Instant::now();
+        // RFC 6298: take at most one RTT measurement per ACK. A cumulative ACK
+        // may retire several segments at once, so sample only the oldest of
+        // them that was never retransmitted (Karn's algorithm).
+        let mut sampled = false;
+        while let Some(front) = self.unacked.front() {
+            let seg_end = front.seq.wrapping_add(front.len());
+            // fully acknowledged iff send.una <= seg_end <= ackn
+            if !is_between_wrapped(self.send.una.wrapping_sub(1), seg_end, ackn.wrapping_add(1)) {
+                break;
+            }
+            if !sampled && !front.retransmitted {
+                self.timers.sample(now.duration_since(front.sent));
+                sampled = true;
+            }
+            self.unacked.pop_front();
+        }
+    }
+
+    /// Effective send window: the peer's advertised window clamped by the
+    /// congestion window, so we never outpace the network's capacity.
+    pub(crate) fn send_window(&self) -> u32 {
+        // the peer advertises its window shifted right by its window scale
+        let peer = (self.send.wnd as u32) << self.send_wscale;
+        std::cmp::min(peer, self.congestion.window())
+    }
+
+    /// Resend the oldest unacked segment immediately, marking it retransmitted
+    /// so Karn's algorithm skips its RTT sample. Shared by the retransmission
+    /// timer and by fast retransmit.
+    fn retransmit_oldest(&mut self, nic: &mut impl Nic) -> io::Result<()> {
+        let seg = match self.unacked.front() {
+            Some(front) => (front.seq, front.syn, front.fin, front.data.clone()),
+            None => return Ok(()),
+        };
+        let (seq, syn, fin, data) = seg;
+        self.send_segment(nic, seq, syn, fin, &data)?;
+        let front = self.unacked.front_mut().expect("queue is non-empty");
+        front.sent = Instant::now();
+        front.retransmitted = true;
+        Ok(())
+    }
+
+    /// Earliest deadline the event loop must wake for: the retransmission
+    /// timer of the oldest unacked segment and, in TIME-WAIT, the 2·MSL
+    /// teardown timer.
+    pub(crate) fn next_timeout(&self) -> Option<Instant> {
+        let retx = self.unacked.front().map(|s| s.sent + self.timers.rto);
+        let time_wait = match (&self.state, self.time_wait_at) {
+            (State::TimeWait, Some(entered)) => Some(entered + 2 * MSL),
+            _ => None,
+        };
+        match (retx, time_wait) {
+            (Some(a), Some(b)) => Some(std::cmp::min(a, b)),
+            (a, b) => a.or(b),
+        }
+    }
+
+    /// Fire any expired timers. In TIME-WAIT, expiry of the 2·MSL timer tears
+    /// the connection down (→ CLOSED) and asks the event loop to reap it.
+    /// Otherwise, if the oldest unacked segment has been outstanding longer
+    /// than the current RTO, resend it, back the RTO off and collapse
+    /// congestion control back to slow start.
+    pub(crate) fn on_tick(&mut self, nic: &mut impl Nic) -> io::Result<Disposition> {
+        if let State::TimeWait = self.state {
+            if let Some(entered) = self.time_wait_at {
+                if Instant::now().duration_since(entered) >= 2 * MSL {
+                    self.state = State::Closed;
+                    return Ok(Disposition::Remove);
+                }
+            }
+        }
+
+        let due = self
+            .unacked
+            .front()
+            .is_some_and(|f| Instant::now().duration_since(f.sent) >= self.timers.rto);
+        if due {
+            self.retransmit_oldest(nic)?;
+            self.timers.backoff();
+            self.congestion.on_timeout();
+        }
+        Ok(self.disposition())
+    }
+
+    /// Whether the connection should be kept or reaped from the quad table.
+    fn disposition(&self) -> Disposition {
+        match self.state {
+            State::Closed => Disposition::Remove,
+            _ => Disposition::Keep,
+        }
+    }
+
+    /// React to an unacceptable segment (RFC 793 S3.4).
+    ///
+    /// In a synchronized state (ESTABLISHED, FIN-WAIT-1/2, CLOSE-WAIT,
+    /// CLOSING, LAST-ACK, TIME-WAIT) we must *not* reset: an unacceptable
+    /// segment elicits only an empty ACK carrying the current send-sequence
+    /// number and the next sequence number expected, and we stay in the same
+    /// state. In an unsynchronized state we send a RST whose numbers are
+    /// derived from the offending segment.
+    fn send_rst(
+        &mut self,
+        nic: &mut impl Nic,
+        tcph: &etherparse::TcpHeaderSlice,
+        data: &[u8],
+    ) -> io::Result<()> {
+        if self.state.is_synchonized() {
+            self.tcp.rst = false;
+            self.tcp.ack = true;
+            self.write(nic, &[])?;
+            return Ok(());
+        }
+
         self.tcp.rst = true;
-        // TODO: fix sequence numbers here
-        // If the incoming segment has an ACK field, the reset takes its
-        // sequence number from the ACK field of the segment, otherwise the
-        // reset has sequence number zero and the ACK field is set to the sum
-        // of the sequence number and segment length of the incoming segment.
-        // The connection remains in the same state.
-        //
-        // TODO: handle synchronized RST
-        // 3.  If the connection is in a synchronized state (ESTABLISHED,
-        // FIN-WAIT-1, FIN-WAIT-2, CLOSE-WAIT, CLOSING, LAST-ACK, TIME-WAIT),
-        // any unacceptable segment (out of window sequence number or
-        // unacceptible acknowledgment number) must elicit only an empty
-        // acknowledgment segment containing the current send-sequence number
-        // and an acknowledgment indicating the next sequence number expected
-        // to be received, and the connection remains in the same state.
-        self.tcp.sequence_number = 0;
-        self.tcp.acknowledgment_number = 0;
-        self.write(nic, &[])?;
+        self.tcp.syn = false;
+        self.tcp.fin = false;
+        if tcph.ack() {
+            // the reset takes its sequence number from the segment's ACK field
+            // and carries no ACK of its own
+            self.tcp.sequence_number = tcph.acknowledgment_number();
+            self.tcp.acknowledgment_number = 0;
+            self.tcp.ack = false;
+        } else {
+            // otherwise seq is zero and we acknowledge past the whole segment
+            let seg_len = data.len() as u32 + tcph.syn() as u32 + tcph.fin() as u32;
+            self.tcp.sequence_number = 0;
+            self.tcp.acknowledgment_number = tcph.sequence_number().wrapping_add(seg_len);
+            self.tcp.ack = true;
+        }
+        self.send_raw(nic)?;
+        self.tcp.rst = false;
+        Ok(())
+    }
+
+    /// Serialise and transmit the current header with no payload, using the
+    /// sequence/acknowledgement numbers and flags already set on `self.tcp`.
+    /// Unlike `write`/`send_segment` it assigns no numbers and touches no
+    /// sequence space, so it can emit control segments like RST verbatim.
+    fn send_raw(&mut self, nic: &mut impl Nic) -> io::Result<()> {
+        let mut buf = [0u8; 1500];
+        self.tcp.set_options(&[]).ok();
+        self.ip.set_payload_len(self.tcp.header_len() as usize);
+        self.tcp.checksum = self
+            .tcp
+            .calc_checksum_ipv4(&self.ip, &[])
+            .expect("failed to compute checksum");
+
+        use std::io::Write;
+        let mut unwritten = &mut buf[..];
+        self.ip.write(&mut unwritten);
+        self.tcp.write(&mut unwritten);
+        let unwritten = unwritten.len();
+        nic.send(&buf[..buf.len() - unwritten])?;
+        Ok(())
+    }
+
+    /// Emit a RST for a segment that arrived with no matching TCB (RFC 793
+    /// S3.4 case 1). Used by the listen/accept path for stray non-SYN packets.
+    pub(crate) fn send_reset(
+        nic: &mut impl Nic,
+        iph: &etherparse::Ipv4HeaderSlice,
+        tcph: &etherparse::TcpHeaderSlice,
+        data: &[u8],
+    ) -> io::Result<()> {
+        let mut tcp =
+            etherparse::TcpHeader::new(tcph.destination_port(), tcph.source_port(), 0, 0);
+        tcp.rst = true;
+        if tcph.ack() {
+            tcp.sequence_number = tcph.acknowledgment_number();
+        } else {
+            let seg_len = data.len() as u32 + tcph.syn() as u32 + tcph.fin() as u32;
+            tcp.acknowledgment_number = tcph.sequence_number().wrapping_add(seg_len);
+            tcp.ack = true;
+        }
+        let mut ip = etherparse::Ipv4Header::new(
+            0,
+            64,
+            6,
+            iph.destination().into(),
+            iph.source().into(),
+        );
+        ip.set_payload_len(tcp.header_len() as usize);
+        tcp.checksum = tcp
+            .calc_checksum_ipv4(&ip, &[])
+            .expect("failed to compute checksum");
+
+        let mut buf = [0u8; 1500];
+        use std::io::Write;
+        let mut unwritten = &mut buf[..];
+        ip.write(&mut unwritten);
+        tcp.write(&mut unwritten);
+        let unwritten = unwritten.len();
+        nic.send(&buf[..buf.len() - unwritten])?;
         Ok(())
     }
 }
@@ -378,3 +1104,133 @@ fn is_between_wrapped(start: u32, x: u32, end: u32) -> bool {
     }
     true
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn contiguous_in_order() {
+        let mut a = Assembler::new(1024);
+        a.add(0, 100);
+        assert_eq!(a.contiguous(), 100);
+        a.add(100, 50);
+        assert_eq!(a.contiguous(), 150);
+    }
+
+    #[test]
+    fn out_of_order_leaves_a_hole_until_filled() {
+        let mut a = Assembler::new(1024);
+        // a segment landing past the left edge buffers but does not advance
+        a.add(100, 50);
+        assert_eq!(a.contiguous(), 0);
+        // filling the gap exposes the whole contiguous run
+        a.add(0, 100);
+        assert_eq!(a.contiguous(), 150);
+    }
+
+    #[test]
+    fn overlapping_adds_merge() {
+        let mut a = Assembler::new(1024);
+        a.add(0, 60);
+        a.add(40, 60);
+        assert_eq!(a.contiguous(), 100);
+    }
+
+    #[test]
+    fn add_spanning_the_whole_window_is_fully_contiguous() {
+        let mut a = Assembler::new(1024);
+        a.add(0, 1024);
+        assert_eq!(a.contiguous(), 1024);
+        assert!(a.holes.is_empty());
+    }
+
+    #[test]
+    fn add_at_the_right_edge_of_the_window() {
+        // the out-of-order case that used to index past a 1024-byte buffer:
+        // an offset near the window edge must stay in bounds here.
+        let mut a = Assembler::new(1024);
+        a.add(1000, 24);
+        assert_eq!(a.contiguous(), 0);
+        a.add(0, 1000);
+        assert_eq!(a.contiguous(), 1024);
+    }
+
+    #[test]
+    fn consume_slides_the_window() {
+        let mut a = Assembler::new(1024);
+        a.add(0, 100);
+        let n = a.contiguous();
+        a.consume(n);
+        // the freshly exposed bytes on the right are still one big hole
+        assert_eq!(a.contiguous(), 0);
+        assert_eq!(a.holes, vec![(0, 1024)]);
+    }
+
+    #[test]
+    fn sample_seeds_then_smooths_the_estimator() {
+        let mut t = Timers::new();
+        t.sample(Duration::from_millis(200));
+        assert!((t.srtt - 0.2).abs() < 1e-9);
+        assert!((t.rttvar - 0.1).abs() < 1e-9);
+        // a second sample must move srtt toward the new value, not replace it
+        let before = t.srtt;
+        t.sample(Duration::from_millis(400));
+        assert!(t.srtt > before && t.srtt < 0.4);
+    }
+
+    #[test]
+    fn rto_never_drops_below_the_floor() {
+        let mut t = Timers::new();
+        t.sample(Duration::from_millis(1));
+        assert_eq!(t.rto, Timers::MIN_RTO);
+        t.backoff();
+        assert_eq!(t.rto, 2 * Timers::MIN_RTO);
+    }
+
+    /// A transmit sink that discards every segment, so the state machine can be
+    /// driven without a live NIC.
+    struct NullNic;
+
+    impl Nic for NullNic {
+        fn send(&mut self, buf: &[u8]) -> io::Result<usize> {
+            Ok(buf.len())
+        }
+    }
+
+    /// Serialise a bare SYN-ACK with the given sequence and acknowledgement
+    /// numbers so it can be fed back in as a `TcpHeaderSlice`.
+    fn syn_ack(seq: u32, ack: u32) -> Vec<u8> {
+        let mut h = etherparse::TcpHeader::new(9000, 8000, seq, 1024);
+        h.syn = true;
+        h.ack = true;
+        h.acknowledgment_number = ack;
+        let mut buf = Vec::new();
+        h.write(&mut buf).expect("serialise SYN-ACK");
+        buf
+    }
+
+    #[test]
+    fn active_open_reaches_established() {
+        let mut nic = NullNic;
+        let src = (std::net::Ipv4Addr::new(10, 0, 0, 1), 8000);
+        let dst = (std::net::Ipv4Addr::new(10, 0, 0, 2), 9000);
+        let mut c = Connection::connect(&mut nic, src, dst).expect("send SYN");
+        assert!(matches!(c.state, State::SynSent));
+        // our SYN (seq 0) sits in the retransmission queue awaiting its ACK
+        assert_eq!(c.send.nxt, 1);
+        assert_eq!(c.unacked.len(), 1);
+
+        // peer answers with a SYN-ACK acknowledging our SYN
+        let bytes = syn_ack(1000, 1);
+        let tcph = etherparse::TcpHeaderSlice::from_slice(&bytes).expect("parse SYN-ACK");
+        c.on_synsent(&mut nic, &tcph).expect("handle SYN-ACK");
+
+        assert!(matches!(c.state, State::Estab));
+        // our SYN is acknowledged and the queue has drained
+        assert_eq!(c.send.una, 1);
+        assert!(c.unacked.is_empty());
+        // we recorded the peer's ISS and advance past its SYN
+        assert_eq!(c.recv.nxt, 1001);
+    }
+}
@@ -0,0 +1,141 @@
+//! Sender-side congestion control: Reno-style slow start and congestion
+//! avoidance with optional fast retransmit / fast recovery, mirroring the
+//! structure of the Fuchsia netstack3 `congestion` module. The effective send
+//! window is the minimum of the peer's advertised window and `cwnd`.
+
+/// Tracks the congestion window and slow-start threshold for one connection.
+pub(super) struct CongestionControl {
+    /// maximum segment size driving the window increments
+    mss: u32,
+    /// congestion window, in bytes
+    cwnd: u32,
+    /// slow-start threshold: below it we slow-start, at or above we avoid
+    ssthresh: u32,
+    /// run of duplicate ACKs seen since the last new ACK
+    dup_acks: u32,
+}
+
+impl CongestionControl {
+    /// Start in slow start with a one-segment window and an unbounded
+    /// threshold, so the connection probes capacity from scratch.
+    pub(super) fn new(mss: u32) -> Self {
+        CongestionControl {
+            mss,
+            cwnd: mss,
+            ssthresh: u32::MAX,
+            dup_acks: 0,
+        }
+    }
+
+    /// Current congestion window in bytes.
+    pub(super) fn window(&self) -> u32 {
+        self.cwnd
+    }
+
+    /// A new (non-duplicate) ACK advanced `send.una`: open the window. In slow
+    /// start `cwnd` grows by one MSS per ACK (doubling each RTT); in
+    /// congestion avoidance it grows by roughly `MSS*MSS/cwnd` per ACK, i.e.
+    /// about one MSS per RTT.
+    pub(super) fn on_ack(&mut self) {
+        self.dup_acks = 0;
+        if self.cwnd < self.ssthresh {
+            self.cwnd = self.cwnd.saturating_add(self.mss);
+        } else {
+            let inc = (self.mss as u64 * self.mss as u64 / self.cwnd as u64) as u32;
+            self.cwnd = self.cwnd.saturating_add(inc.max(1));
+        }
+    }
+
+    /// A retransmission timeout: set the threshold to half the congestion
+    /// window (at least two segments) and drop back to slow start with a
+    /// one-segment window.
+    pub(super) fn on_timeout(&mut self) {
+        self.ssthresh = std::cmp::max(self.cwnd / 2, 2 * self.mss);
+        self.cwnd = self.mss;
+        self.dup_acks = 0;
+    }
+
+    /// A duplicate ACK arrived. Returns `true` on the third one, meaning the
+    /// caller should fast-retransmit the missing segment; `cwnd` is halved
+    /// into fast recovery rather than collapsing to one segment.
+    pub(super) fn on_dup_ack(&mut self) -> bool {
+        self.dup_acks += 1;
+        if self.dup_acks == 3 {
+            self.ssthresh = std::cmp::max(self.cwnd / 2, 2 * self.mss);
+            self.cwnd = self.ssthresh;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MSS: u32 = 1460;
+
+    #[test]
+    fn starts_in_slow_start_with_one_segment() {
+        let cc = CongestionControl::new(MSS);
+        assert_eq!(cc.window(), MSS);
+    }
+
+    #[test]
+    fn slow_start_grows_by_one_segment_per_ack() {
+        let mut cc = CongestionControl::new(MSS);
+        cc.on_ack();
+        assert_eq!(cc.window(), 2 * MSS);
+        cc.on_ack();
+        assert_eq!(cc.window(), 3 * MSS);
+    }
+
+    #[test]
+    fn avoidance_grows_by_under_a_segment_per_ack() {
+        let mut cc = CongestionControl::new(MSS);
+        // open the window to two segments, then force congestion avoidance
+        // by pinning the threshold below cwnd
+        cc.on_ack();
+        cc.ssthresh = MSS;
+        cc.on_ack();
+        assert!(cc.window() > 2 * MSS && cc.window() < 3 * MSS);
+    }
+
+    #[test]
+    fn timeout_collapses_to_slow_start() {
+        let mut cc = CongestionControl::new(MSS);
+        for _ in 0..8 {
+            cc.on_ack();
+        }
+        let before = cc.window();
+        cc.on_timeout();
+        assert_eq!(cc.window(), MSS);
+        assert_eq!(cc.ssthresh, std::cmp::max(before / 2, 2 * MSS));
+    }
+
+    #[test]
+    fn third_dup_ack_triggers_fast_retransmit() {
+        let mut cc = CongestionControl::new(MSS);
+        for _ in 0..8 {
+            cc.on_ack();
+        }
+        let before = cc.window();
+        assert!(!cc.on_dup_ack());
+        assert!(!cc.on_dup_ack());
+        assert!(cc.on_dup_ack());
+        // fast recovery halves the window rather than collapsing it
+        assert_eq!(cc.window(), std::cmp::max(before / 2, 2 * MSS));
+    }
+
+    #[test]
+    fn a_new_ack_resets_the_dup_ack_run() {
+        let mut cc = CongestionControl::new(MSS);
+        assert!(!cc.on_dup_ack());
+        assert!(!cc.on_dup_ack());
+        cc.on_ack();
+        // the run restarts, so the next two dups do not fast-retransmit
+        assert!(!cc.on_dup_ack());
+        assert!(!cc.on_dup_ack());
+    }
+}